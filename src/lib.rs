@@ -5,7 +5,7 @@
 //! let's model a turnstile instead
 //!
 //! ```
-//! use machine::{Machine, MachineError, State};
+//! use machine::{DispatchResultExt, FatalError, Machine, MachineError, State};
 //!
 //! #[derive(Clone, Copy, Debug, PartialEq)]
 //! enum TurnstileState {
@@ -13,32 +13,40 @@
 //!   Unlocked,
 //! }
 //!
-//! #[derive(Debug, PartialEq)]
+//! #[derive(Clone, Debug, PartialEq)]
 //! enum TurnstileEvent {
 //!   PaymentReceived,
 //!   PersonEntering,
 //! }
 //!
 //! impl State<TurnstileEvent> for TurnstileState {
-//!   fn apply(&self, event: TurnstileEvent) -> Result<Self, MachineError<Self, TurnstileEvent>> {
+//!   // the turnstile has no side-effects to enact, so its `Action` is just `()` and it never requests one
+//!   type Action = ();
+//!
+//!   // see `FatalError` for what the outer vs. inner `Result` each mean
+//!   fn apply(&self, event: TurnstileEvent) -> Result<Result<(Self, Vec<Self::Action>), MachineError<Self, TurnstileEvent>>, FatalError<Self, TurnstileEvent>> {
 //!     match self {
 //!       Self::Locked => match event {
-//!         TurnstileEvent::PaymentReceived => Ok( Self::Unlocked ),
+//!         TurnstileEvent::PaymentReceived => Ok( Ok( (Self::Unlocked, vec![]) ) ),
 //!         // replace string error
 //!         // _ => Err("Payment required for entry.".to_owned())
 //!         // w/ actual error type
-//!         _ => Err(MachineError::InvalidEvent(*self, event))
+//!         _ => Ok( Err(MachineError::InvalidEvent(*self, event)) )
 //!       }
 //!
 //!       Self::Unlocked => match event {
-//!         TurnstileEvent::PersonEntering => Ok( Self::Locked ),
+//!         TurnstileEvent::PersonEntering => Ok( Ok( (Self::Locked, vec![]) ) ),
 //!         // replace string error
 //!         // _ => Err("Payment already received, unable to accept payment at this time.".to_owned())
 //!         // w/ actual error type
-//!         _ => Err(MachineError::InvalidEvent(*self, event))
+//!         _ => Ok( Err(MachineError::InvalidEvent(*self, event)) )
 //!       }
 //!     }
 //!   }
+//!
+//!   fn act(_action: Self::Action) -> Vec<TurnstileEvent> {
+//!     vec![]
+//!   }
 //! }
 //!
 //! let mut machine = Machine::new(TurnstileState::Locked);
@@ -49,13 +57,21 @@
 //! // event, the machine instead returns an error
 //!
 //! // try to enter w/out paying
-//! let locked_err = machine.dispatch(TurnstileEvent::PersonEntering).expect_err("Shouldn't be able to enter without paying");
+//! // `collapse` lets a caller `?`-propagate a fatal error (here, just panicking on
+//! // it since the turnstile is never expected to hit one) while still matching on
+//! // the ordinary, recoverable outcome
+//! let locked_err = machine
+//!     .dispatch(TurnstileEvent::PersonEntering)
+//!     .collapse(|fatal| panic!("unexpectedly poisoned: {fatal:?}"), |e| e)
+//!     .expect_err("Shouldn't be able to enter without paying");
 //! // descriptive error is returned
 //! assert_eq!(
 //!     locked_err,
 //!     MachineError::InvalidEvent(TurnstileState::Locked, TurnstileEvent::PersonEntering));
 //! // and turnstile remains locked
 //! assert_eq!(machine.state, TurnstileState::Locked);
+//! // the machine hasn't hit a fatal error, so it's still accepting events
+//! assert!(!machine.is_poisoned());
 //! // so we pay as we're instructed to
 //! machine.dispatch(TurnstileEvent::PaymentReceived);
 //! assert_eq!(machine.state, TurnstileState::Unlocked); // and the turnstile unlocks
@@ -65,7 +81,10 @@
 //!
 //! // or if we try to pay twice, we also get a helpful error
 //! machine.dispatch(TurnstileEvent::PaymentReceived); // pay once here, then again below
-//! let paid_err = machine.dispatch(TurnstileEvent::PaymentReceived).expect_err("Shouldn't be able to enter without paying");
+//! let paid_err = machine
+//!     .dispatch(TurnstileEvent::PaymentReceived)
+//!     .collapse(|fatal| panic!("unexpectedly poisoned: {fatal:?}"), |e| e)
+//!     .expect_err("Shouldn't be able to pay twice");
 //! assert_eq!(
 //!     paid_err,
 //!     MachineError::InvalidEvent(TurnstileState::Unlocked, TurnstileEvent::PaymentReceived));
@@ -73,13 +92,40 @@
 //! assert_eq!(machine.state, TurnstileState::Unlocked);
 //! ```
 
+use std::collections::VecDeque;
 use std::{error, fmt, marker::PhantomData};
 
+pub mod storage;
+pub use storage::{InMemoryStorage, MachineId, PersistentMachine, PersistentMachineError, Storage};
+
+pub mod async_machine;
+pub use async_machine::{AsyncMachine, AsyncState};
+
+/// A predicate matched against a machine's state, used to scope an
+/// [`on_enter`](Machine::on_enter)/[`on_exit`](Machine::on_exit) hook to the
+/// states it applies to.
+type StatePredicate<StateType> = Box<dyn Fn(&StateType) -> bool>;
+
+/// An entry/exit side-effect run when a machine's state changes into or out
+/// of a state matched by its [`StatePredicate`].
+type StateHook<StateType> = Box<dyn FnMut(&StateType)>;
+
+/// A cross-cutting check run before a transition is applied; returning
+/// `false` short-circuits the transition with `MachineError::Guarded`
+/// without mutating state.
+type Guard<StateType, Event> = Box<dyn FnMut(&StateType, &Event) -> bool>;
+
 pub struct Machine<StateType, Event>
 where
     StateType: State<Event>,
 {
     pub state: StateType,
+    poison: Option<FatalError<StateType, Event>>,
+    #[cfg(feature = "tracing")]
+    traced: bool,
+    guards: Vec<Guard<StateType, Event>>,
+    on_enter: Vec<(StatePredicate<StateType>, StateHook<StateType>)>,
+    on_exit: Vec<(StatePredicate<StateType>, StateHook<StateType>)>,
     // FIXME: remove this phantom data when possible
     _event: PhantomData<Event>,
 }
@@ -91,21 +137,199 @@ where
     pub fn new(initial_state: StateType) -> Self {
         Self {
             state: initial_state,
+            poison: None,
+            #[cfg(feature = "tracing")]
+            traced: false,
+            guards: Vec::new(),
+            on_enter: Vec::new(),
+            on_exit: Vec::new(),
             _event: PhantomData,
         }
     }
 
-    pub fn dispatch(&mut self, event: Event) -> Result<(), MachineError<StateType, Event>> {
-        self.state = self.state.apply(event).map_err(|e| e.into())?;
+    /// Like [`new`](Self::new), but every `dispatch` opens a root
+    /// [`tracing`] span for the externally triggered event, and every
+    /// transition applied while processing it -- the trigger itself, and any
+    /// derived events fed back through `act` -- opens its own child span
+    /// recording the current state, the event, and the resulting state (or
+    /// error). The full causal tree of one external trigger is reconstructable
+    /// in a log/trace viewer.
+    #[cfg(feature = "tracing")]
+    pub fn new_traced(initial_state: StateType) -> Self {
+        Self {
+            traced: true,
+            ..Self::new(initial_state)
+        }
+    }
+
+    /// Register a guard that runs before every transition. If `guard`
+    /// returns `false` for the current state and event, the transition is
+    /// rejected with `MachineError::Guarded` and `self.state` is left
+    /// untouched. Guards run in registration order; the first to fail wins.
+    pub fn guard(mut self, guard: impl FnMut(&StateType, &Event) -> bool + 'static) -> Self {
+        self.guards.push(Box::new(guard));
+        self
+    }
+
+    /// Register a hook that runs after any transition that leaves the
+    /// machine in a state matched by `predicate`, but only when the state
+    /// actually changed.
+    pub fn on_enter(
+        mut self,
+        predicate: impl Fn(&StateType) -> bool + 'static,
+        hook: impl FnMut(&StateType) + 'static,
+    ) -> Self {
+        self.on_enter.push((Box::new(predicate), Box::new(hook)));
+        self
+    }
+
+    /// Register a hook that runs after any transition that leaves a state
+    /// matched by `predicate`, but only when the state actually changed.
+    pub fn on_exit(
+        mut self,
+        predicate: impl Fn(&StateType) -> bool + 'static,
+        hook: impl FnMut(&StateType) + 'static,
+    ) -> Self {
+        self.on_exit.push((Box::new(predicate), Box::new(hook)));
+        self
+    }
+
+    /// Whether this machine has hit a fatal error. Once poisoned, a machine
+    /// never touches `self.state` again: every subsequent `dispatch` call
+    /// immediately returns the same fatal error.
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.is_some()
+    }
+
+    /// Apply `event` to the current state, then drive any actions it requests
+    /// to completion: each action is enacted via `State::act`, and any events
+    /// that produces are fed back through `apply` until none remain, in the
+    /// order they were produced (breadth-first across nested actions, not
+    /// depth-first) and without recursing -- a long chain of derived events
+    /// can't overflow the stack.
+    ///
+    /// State transitions stay pure and synchronously testable (`apply` only
+    /// ever describes what *should* happen); `act` is where the actual
+    /// side-effects (I/O, sending email, etc.) live.
+    ///
+    /// If any event partway through the chain is rejected or hits a fatal
+    /// error, `dispatch` returns immediately and any events still queued
+    /// behind it are discarded without being applied. State committed by
+    /// earlier events in the same chain is not rolled back, so a failing
+    /// `dispatch` can still leave the machine having made partial progress.
+    ///
+    /// See [`FatalError`] for what the outer vs. inner `Result` each mean.
+    pub fn dispatch(
+        &mut self,
+        event: Event,
+    ) -> Result<Result<(), MachineError<StateType, Event>>, FatalError<StateType, Event>>
+    where
+        StateType: Clone + fmt::Debug + PartialEq,
+        Event: Clone + fmt::Debug,
+    {
+        if let Some(poison) = &self.poison {
+            return Err(poison.clone());
+        }
+
+        #[cfg(feature = "tracing")]
+        let _root = self
+            .traced
+            .then(|| tracing::info_span!("dispatch", event = ?event).entered());
+
+        let mut pending = VecDeque::from([event]);
+        while let Some(event) = pending.pop_front() {
+            match self.dispatch_one(event, &mut pending) {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => return Ok(Err(e)),
+                Err(fatal) => return Err(fatal),
+            }
+        }
+
+        Ok(Ok(()))
+    }
+
+    /// Apply a single event (either the externally dispatched one, or one
+    /// popped off `pending`), pushing any events *it* derives onto `pending`
+    /// rather than recursing into them directly. Opens its own `tracing`
+    /// span per event; since `dispatch`'s root span stays entered for the
+    /// whole call, every one of these nests as its child, not just the
+    /// externally triggered event.
+    fn dispatch_one(
+        &mut self,
+        event: Event,
+        pending: &mut VecDeque<Event>,
+    ) -> Result<Result<(), MachineError<StateType, Event>>, FatalError<StateType, Event>>
+    where
+        StateType: Clone + fmt::Debug + PartialEq,
+        Event: Clone + fmt::Debug,
+    {
+        #[cfg(feature = "tracing")]
+        let _span = self.traced.then(|| {
+            tracing::info_span!("transition", state = ?self.state, event = ?event).entered()
+        });
+
+        for guard in self.guards.iter_mut() {
+            if !guard(&self.state, &event) {
+                return Ok(Err(MachineError::Guarded(self.state.clone(), event)));
+            }
+        }
+
+        let outcome = self
+            .state
+            .apply(event)
+            .inspect_err(|fatal| self.poison = Some(fatal.clone()))?;
+
+        let (new_state, actions) = match outcome {
+            Ok(transition) => transition,
+            Err(e) => {
+                #[cfg(feature = "tracing")]
+                if self.traced {
+                    tracing::warn!(error = ?e, "transition rejected");
+                }
+
+                return Ok(Err(e));
+            }
+        };
+
+        let old_state = std::mem::replace(&mut self.state, new_state);
+
+        #[cfg(feature = "tracing")]
+        if self.traced {
+            tracing::debug!(state = ?self.state, "transition committed");
+        }
+
+        if old_state != self.state {
+            for (predicate, hook) in self.on_exit.iter_mut() {
+                if predicate(&old_state) {
+                    hook(&old_state);
+                }
+            }
+
+            for (predicate, hook) in self.on_enter.iter_mut() {
+                if predicate(&self.state) {
+                    hook(&self.state);
+                }
+            }
+        }
+
+        for action in actions {
+            pending.extend(StateType::act(action));
+        }
 
         // FIXME: not sure if it'd be more helpful to return a value here
-        Ok(())
+        Ok(Ok(()))
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum MachineError<State, Event> {
     InvalidEvent(State, Event),
+    /// Returned by [`AsyncMachine::dispatch`](async_machine::AsyncMachine::dispatch)
+    /// when a transition is already in flight for this machine.
+    Busy,
+    /// Returned when a [`Machine::guard`] rejects the event for the current
+    /// state; `self.state` is left untouched.
+    Guarded(State, Event),
 }
 
 impl<State, Event> fmt::Display for MachineError<State, Event>
@@ -118,6 +342,10 @@ where
             MachineError::InvalidEvent(ref s, ref e) => {
                 write!(f, "Invalid Event, {e} for State {s}")
             }
+            MachineError::Busy => write!(f, "A transition is already in flight for this machine"),
+            MachineError::Guarded(ref s, ref e) => {
+                write!(f, "Event {e} for State {s} was rejected by a guard")
+            }
         }
     }
 }
@@ -138,8 +366,351 @@ where
     // }
 }
 
+/// The non-fatal half of [`State::apply`]'s return value: the next state and
+/// any actions to enact, or the ordinary `ErrorType` for a rejected event.
+pub type ApplyOutcome<StateType, Action, ErrorType> = Result<(StateType, Vec<Action>), ErrorType>;
+
 pub trait State<Event, ErrorType = MachineError<Self, Event>> {
-    fn apply(&self, event: Event) -> Result<Self, ErrorType>
+    /// A declarative description of a side-effect a transition wants
+    /// performed, e.g. "send an email" or "charge a card". Actions are
+    /// requested by `apply` but only carried out by `act`, so transition
+    /// logic stays pure and unit-testable without ever touching I/O.
+    type Action;
+
+    /// Compute the next state for `event`, along with any actions that
+    /// should be enacted as a result of the transition.
+    ///
+    /// See [`FatalError`] for what the outer vs. inner `Result` each mean.
+    fn apply(
+        &self,
+        event: Event,
+    ) -> Result<ApplyOutcome<Self, Self::Action, ErrorType>, FatalError<Self, Event>>
     where
         Self: Sized;
+
+    /// Perform `action`, returning any follow-up events it produces. This is
+    /// where actual side-effects happen; `Machine::dispatch` feeds the
+    /// returned events back through `apply`.
+    fn act(action: Self::Action) -> Vec<Event>;
+}
+
+/// A fatal, machine-poisoning failure, as distinct from the ordinary,
+/// recoverable outcomes carried by [`MachineError`].
+///
+/// This is why `State::apply`, `Machine::dispatch`, and friends all return a
+/// nested `Result<Result<T, MachineError<..>>, FatalError<..>>`: the outer
+/// `Result` is for fatal failures -- a violated invariant that should halt
+/// the machine entirely -- and can be `?`-propagated by callers, while the
+/// inner one is the ordinary, recoverable outcome of a transition (e.g. an
+/// invalid event for the current state) that callers routinely match on
+/// instead. [`DispatchResultExt::collapse`] folds the two into a single
+/// `Result` when a caller wants that instead.
+///
+/// Once a `Machine` sees one of these from `State::apply`, it stops touching
+/// `self.state` entirely: every subsequent `dispatch` call immediately
+/// returns the same `FatalError` without attempting another transition.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FatalError<State, Event> {
+    /// An internal invariant was violated in a way recovery shouldn't be
+    /// attempted for.
+    Corrupted(MachineError<State, Event>),
+}
+
+impl<State, Event> FatalError<State, Event> {
+    /// The underlying recoverable-shaped error that triggered this fatal
+    /// failure, e.g. for logging.
+    pub fn into_inner(self) -> MachineError<State, Event> {
+        match self {
+            Self::Corrupted(e) => e,
+        }
+    }
+}
+
+impl<State, Event> fmt::Display for FatalError<State, Event>
+where
+    State: fmt::Display,
+    Event: fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Corrupted(e) => write!(f, "Machine poisoned by a fatal error: {e}"),
+        }
+    }
+}
+
+impl<State, Event> error::Error for FatalError<State, Event>
+where
+    State: fmt::Display + fmt::Debug,
+    Event: fmt::Display + fmt::Debug,
+{
+}
+
+/// Ergonomic folding for the nested `Result` returned by `dispatch`/`apply`
+/// (see [`FatalError`]), so callers who don't want to match on it by hand can
+/// collapse both halves into a single `Result` in one step.
+pub trait DispatchResultExt<T, Recoverable, State, Event> {
+    /// Fold the fatal and recoverable halves of a dispatch result into a
+    /// single `Result<T, E>`, via whichever of `on_fatal`/`on_recoverable`
+    /// matches. A caller that just wants to `?`-propagate the fatal half
+    /// unchanged can pass `Into::into` for `on_fatal`.
+    fn collapse<E>(
+        self,
+        on_fatal: impl FnOnce(FatalError<State, Event>) -> E,
+        on_recoverable: impl FnOnce(Recoverable) -> E,
+    ) -> Result<T, E>;
+}
+
+impl<T, Recoverable, State, Event> DispatchResultExt<T, Recoverable, State, Event>
+    for Result<Result<T, Recoverable>, FatalError<State, Event>>
+{
+    fn collapse<E>(
+        self,
+        on_fatal: impl FnOnce(FatalError<State, Event>) -> E,
+        on_recoverable: impl FnOnce(Recoverable) -> E,
+    ) -> Result<T, E> {
+        match self {
+            Ok(Ok(t)) => Ok(t),
+            Ok(Err(e)) => Err(on_recoverable(e)),
+            Err(e) => Err(on_fatal(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    /// A state that records every event it applies into `log`, so tests can
+    /// assert the order derived events were actually processed in.
+    #[derive(Debug, Clone, PartialEq)]
+    struct Recorder {
+        log: Vec<&'static str>,
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum RecorderEvent {
+        Root,
+        Leaf(&'static str),
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum RecorderAction {
+        Derive(&'static str),
+    }
+
+    impl State<RecorderEvent> for Recorder {
+        type Action = RecorderAction;
+
+        fn apply(
+            &self,
+            event: RecorderEvent,
+        ) -> Result<
+            ApplyOutcome<Self, Self::Action, MachineError<Self, RecorderEvent>>,
+            FatalError<Self, RecorderEvent>,
+        > {
+            let mut log = self.log.clone();
+            let actions = match event {
+                RecorderEvent::Root => {
+                    log.push("root");
+                    vec![RecorderAction::Derive("a"), RecorderAction::Derive("b")]
+                }
+                RecorderEvent::Leaf("a") => {
+                    log.push("a");
+                    vec![RecorderAction::Derive("a1")]
+                }
+                RecorderEvent::Leaf("b") => {
+                    log.push("b");
+                    vec![RecorderAction::Derive("b1")]
+                }
+                RecorderEvent::Leaf(name) => {
+                    log.push(name);
+                    vec![]
+                }
+            };
+
+            Ok(Ok((Recorder { log }, actions)))
+        }
+
+        fn act(action: Self::Action) -> Vec<RecorderEvent> {
+            match action {
+                RecorderAction::Derive(name) => vec![RecorderEvent::Leaf(name)],
+            }
+        }
+    }
+
+    #[test]
+    fn dispatch_applies_derived_events_breadth_first() {
+        let mut machine = Machine::new(Recorder { log: vec![] });
+
+        machine.dispatch(RecorderEvent::Root).unwrap().unwrap();
+
+        // root's two actions each derive one more leaf; if dispatch recursed
+        // depth-first this would read ["root", "a", "a1", "b", "b1"] instead
+        assert_eq!(machine.state.log, vec!["root", "a", "b", "a1", "b1"]);
+    }
+
+    /// A state whose every transition is fatal, for exercising the poisoning
+    /// path without needing a state that can ever succeed.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct Fragile(u32);
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Explode;
+
+    impl State<Explode> for Fragile {
+        type Action = ();
+
+        fn apply(
+            &self,
+            event: Explode,
+        ) -> Result<ApplyOutcome<Self, Self::Action, MachineError<Self, Explode>>, FatalError<Self, Explode>>
+        {
+            Err(FatalError::Corrupted(MachineError::InvalidEvent(
+                *self, event,
+            )))
+        }
+
+        fn act(_action: Self::Action) -> Vec<Explode> {
+            vec![]
+        }
+    }
+
+    #[test]
+    fn a_fatal_error_poisons_the_machine_and_leaves_state_untouched() {
+        let mut machine = Machine::new(Fragile(7));
+        assert!(!machine.is_poisoned());
+
+        let first = machine.dispatch(Explode);
+        assert!(first.is_err());
+        assert!(machine.is_poisoned());
+        assert_eq!(machine.state, Fragile(7));
+
+        // once poisoned, every subsequent dispatch immediately returns the
+        // same fatal error without attempting another transition
+        let second = machine.dispatch(Explode);
+        assert_eq!(first, second);
+        assert_eq!(machine.state, Fragile(7));
+    }
+
+    #[test]
+    fn collapse_maps_the_success_arm_to_ok() {
+        let result: Result<Result<u32, &str>, FatalError<Fragile, Explode>> = Ok(Ok(42));
+        assert_eq!(result.collapse(|_| "fatal", |e| e), Ok(42));
+    }
+
+    #[test]
+    fn collapse_maps_the_recoverable_arm_via_on_recoverable() {
+        let result: Result<Result<u32, &str>, FatalError<Fragile, Explode>> = Ok(Err("nope"));
+        assert_eq!(result.collapse(|_| "fatal", |e| e), Err("nope"));
+    }
+
+    #[test]
+    fn collapse_maps_the_fatal_arm_via_on_fatal() {
+        let fatal = FatalError::Corrupted(MachineError::InvalidEvent(Fragile(7), Explode));
+        let result: Result<Result<u32, &str>, FatalError<Fragile, Explode>> = Err(fatal);
+        assert_eq!(result.collapse(|_| "fatal", |e| e), Err("fatal"));
+    }
+
+    #[test]
+    fn a_failing_guard_rejects_the_event_and_leaves_state_untouched() {
+        let mut machine = Machine::new(Recorder { log: vec![] }).guard(|_state, _event| false);
+
+        let result = machine.dispatch(RecorderEvent::Root).unwrap();
+
+        assert_eq!(
+            result,
+            Err(MachineError::Guarded(
+                Recorder { log: vec![] },
+                RecorderEvent::Root
+            ))
+        );
+        assert!(machine.state.log.is_empty());
+    }
+
+    #[test]
+    fn guards_run_in_registration_order_and_the_first_to_fail_wins() {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let (calls_a, calls_b) = (calls.clone(), calls.clone());
+
+        let mut machine = Machine::new(Recorder { log: vec![] })
+            .guard(move |_state, _event| {
+                calls_a.borrow_mut().push("a");
+                true
+            })
+            .guard(move |_state, _event| {
+                calls_b.borrow_mut().push("b");
+                false
+            });
+
+        let result = machine.dispatch(RecorderEvent::Root).unwrap();
+
+        assert!(matches!(result, Err(MachineError::Guarded(..))));
+        assert_eq!(*calls.borrow(), vec!["a", "b"]);
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Light {
+        Off,
+        On,
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum LightEvent {
+        Toggle,
+        Noop,
+    }
+
+    impl State<LightEvent> for Light {
+        type Action = ();
+
+        fn apply(
+            &self,
+            event: LightEvent,
+        ) -> Result<ApplyOutcome<Self, Self::Action, MachineError<Self, LightEvent>>, FatalError<Self, LightEvent>>
+        {
+            let next = match event {
+                LightEvent::Toggle => match self {
+                    Self::Off => Self::On,
+                    Self::On => Self::Off,
+                },
+                LightEvent::Noop => *self,
+            };
+
+            Ok(Ok((next, vec![])))
+        }
+
+        fn act(_action: Self::Action) -> Vec<LightEvent> {
+            vec![]
+        }
+    }
+
+    #[test]
+    fn on_enter_and_on_exit_hooks_fire_only_when_state_actually_changes() {
+        let entered = Rc::new(RefCell::new(Vec::new()));
+        let exited = Rc::new(RefCell::new(Vec::new()));
+        let (entered_hook, exited_hook) = (entered.clone(), exited.clone());
+
+        let mut machine = Machine::new(Light::Off)
+            .on_enter(
+                |s| *s == Light::On,
+                move |s| entered_hook.borrow_mut().push(*s),
+            )
+            .on_exit(
+                |s| *s == Light::Off,
+                move |s| exited_hook.borrow_mut().push(*s),
+            );
+
+        // a self-transition doesn't change state, so neither hook fires
+        machine.dispatch(LightEvent::Noop).unwrap().unwrap();
+        assert!(entered.borrow().is_empty());
+        assert!(exited.borrow().is_empty());
+
+        // an actual transition fires both: exiting Off, entering On
+        machine.dispatch(LightEvent::Toggle).unwrap().unwrap();
+        assert_eq!(*entered.borrow(), vec![Light::On]);
+        assert_eq!(*exited.borrow(), vec![Light::Off]);
+    }
 }
@@ -0,0 +1,485 @@
+//! Event-sourced persistence for [`Machine`]s.
+//!
+//! A [`PersistentMachine`] pairs a `Machine` with a [`Storage`] backend so
+//! every dispatched event, and the state it produces, is durably recorded.
+//! That means a machine can be rehydrated after a process restart either by
+//! loading its last saved state, or by replaying its entire event log back
+//! through `State::apply` -- which doubles as a free audit trail.
+
+use std::collections::HashMap;
+use std::{error, fmt};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{FatalError, Machine, MachineError, State};
+
+/// Opaque handle identifying a machine's event log and state within a
+/// [`Storage`] backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MachineId(u64);
+
+/// A backend capable of durably recording a machine's event log and the
+/// states that log produces.
+///
+/// `StateType` and `Event` must be (de)serializable so that real backends
+/// (a database, a file, etc.) can actually write them down.
+pub trait Storage<StateType, Event>
+where
+    StateType: Serialize + DeserializeOwned,
+    Event: Serialize + DeserializeOwned,
+{
+    type Error;
+
+    /// Register a new machine with its initial state, returning the id its
+    /// event log and states will be recorded under.
+    fn insert_machine(&mut self, initial: &StateType) -> Result<MachineId, Self::Error>;
+
+    /// Append `event` to `id`'s durable event log.
+    fn insert_event(&mut self, id: MachineId, event: &Event) -> Result<(), Self::Error>;
+
+    /// Record `state` as `id`'s current state.
+    fn insert_state(&mut self, id: MachineId, state: &StateType) -> Result<(), Self::Error>;
+
+    /// Fetch `id`'s last saved state.
+    fn get_machine(&self, id: MachineId) -> Result<StateType, Self::Error>;
+
+    /// Fetch `id`'s full event log, oldest first.
+    fn get_events(&self, id: MachineId) -> Result<Vec<Event>, Self::Error>;
+}
+
+/// A [`Machine`] whose dispatched events and resulting states are durably
+/// recorded through a [`Storage`] backend.
+pub struct PersistentMachine<S, StateType, Event>
+where
+    S: Storage<StateType, Event>,
+    StateType: State<Event> + Serialize + DeserializeOwned,
+    Event: Serialize + DeserializeOwned,
+{
+    id: MachineId,
+    storage: S,
+    machine: Machine<StateType, Event>,
+}
+
+impl<S, StateType, Event> PersistentMachine<S, StateType, Event>
+where
+    S: Storage<StateType, Event>,
+    StateType: State<Event> + Clone + fmt::Debug + PartialEq + Serialize + DeserializeOwned,
+    Event: Clone + fmt::Debug + Serialize + DeserializeOwned,
+{
+    /// Register a brand new machine with `storage` and start it at
+    /// `initial_state`.
+    pub fn new(mut storage: S, initial_state: StateType) -> Result<Self, S::Error> {
+        let id = storage.insert_machine(&initial_state)?;
+
+        Ok(Self {
+            id,
+            storage,
+            machine: Machine::new(initial_state),
+        })
+    }
+
+    /// Load a previously registered machine from its last saved state.
+    pub fn rehydrate(storage: S, id: MachineId) -> Result<Self, S::Error> {
+        let state = storage.get_machine(id)?;
+
+        Ok(Self {
+            id,
+            storage,
+            machine: Machine::new(state),
+        })
+    }
+
+    /// Reconstruct a previously registered machine by replaying its entire
+    /// event log from `initial_state` through `State::apply`, rather than
+    /// trusting the last saved state.
+    ///
+    /// `dispatch` only ever persists an event once its transition succeeds
+    /// (see below), so a stored log should never contain a rejected event --
+    /// but if one is ever found anyway (e.g. a log written by another
+    /// backend), it's tolerated here exactly as it would have been live: it
+    /// simply didn't change the state, and replay carries on.
+    pub fn rehydrate_by_replay(
+        storage: S,
+        id: MachineId,
+        initial_state: StateType,
+    ) -> Result<Self, PersistentMachineError<S::Error, StateType, Event>> {
+        let events = storage
+            .get_events(id)
+            .map_err(PersistentMachineError::Storage)?;
+
+        let mut machine = Machine::new(initial_state);
+        for event in events {
+            match machine.dispatch(event) {
+                Err(fatal) => return Err(PersistentMachineError::Fatal(fatal)),
+                Ok(Err(_recoverable)) => {}
+                Ok(Ok(())) => {}
+            }
+        }
+
+        Ok(Self {
+            id,
+            storage,
+            machine,
+        })
+    }
+
+    pub fn id(&self) -> MachineId {
+        self.id
+    }
+
+    pub fn state(&self) -> &StateType {
+        &self.machine.state
+    }
+
+    /// Apply `event`, then persist it and the resulting state -- in that
+    /// order -- before returning. A rejected/invalid event never touches the
+    /// durable log at all, so it can't poison replay later; only a
+    /// successful transition is recorded. See [`PersistentDispatchResult`]
+    /// for what the outer vs. inner `Result` each mean.
+    pub fn dispatch(&mut self, event: Event) -> PersistentDispatchResult<S, StateType, Event> {
+        let outcome = self
+            .machine
+            .dispatch(event.clone())
+            .map_err(PersistentMachineError::Fatal)?;
+
+        if outcome.is_ok() {
+            self.storage
+                .insert_event(self.id, &event)
+                .map_err(PersistentMachineError::Storage)?;
+            self.storage
+                .insert_state(self.id, &self.machine.state)
+                .map_err(PersistentMachineError::Storage)?;
+        }
+
+        Ok(outcome)
+    }
+}
+
+/// The result of [`PersistentMachine::dispatch`]: the outer `Result` carries
+/// storage failures alongside the fatal-machine failures described on
+/// [`crate::FatalError`]; the inner one is that same ordinary, recoverable
+/// transition outcome.
+pub type PersistentDispatchResult<S, StateType, Event> = Result<
+    Result<(), MachineError<StateType, Event>>,
+    PersistentMachineError<<S as Storage<StateType, Event>>::Error, StateType, Event>,
+>;
+
+#[derive(Debug)]
+pub enum PersistentMachineError<StorageErr, StateType, Event> {
+    Storage(StorageErr),
+    Transition(MachineError<StateType, Event>),
+    Fatal(FatalError<StateType, Event>),
+}
+
+impl<StorageErr, StateType, Event> fmt::Display for PersistentMachineError<StorageErr, StateType, Event>
+where
+    StorageErr: fmt::Display,
+    StateType: fmt::Display,
+    Event: fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Storage(e) => write!(f, "Storage error: {e}"),
+            Self::Transition(e) => write!(f, "Transition error: {e}"),
+            Self::Fatal(e) => write!(f, "Fatal error: {e}"),
+        }
+    }
+}
+
+impl<StorageErr, StateType, Event> error::Error for PersistentMachineError<StorageErr, StateType, Event>
+where
+    StorageErr: fmt::Display + fmt::Debug,
+    StateType: fmt::Display + fmt::Debug,
+    Event: fmt::Display + fmt::Debug,
+{
+}
+
+/// A [`Storage`] backend that keeps everything in memory. Useful for tests
+/// and single-process use; gives up durability across restarts in exchange
+/// for having no external dependencies.
+#[derive(Default)]
+pub struct InMemoryStorage<StateType, Event> {
+    next_id: u64,
+    states: HashMap<MachineId, StateType>,
+    events: HashMap<MachineId, Vec<Event>>,
+}
+
+impl<StateType, Event> InMemoryStorage<StateType, Event> {
+    pub fn new() -> Self {
+        Self {
+            next_id: 0,
+            states: HashMap::new(),
+            events: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum InMemoryStorageError {
+    UnknownMachine(MachineId),
+}
+
+impl fmt::Display for InMemoryStorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownMachine(id) => write!(f, "No machine recorded under {id:?}"),
+        }
+    }
+}
+
+impl error::Error for InMemoryStorageError {}
+
+impl<StateType, Event> Storage<StateType, Event> for InMemoryStorage<StateType, Event>
+where
+    StateType: Clone + Serialize + DeserializeOwned,
+    Event: Clone + Serialize + DeserializeOwned,
+{
+    type Error = InMemoryStorageError;
+
+    fn insert_machine(&mut self, initial: &StateType) -> Result<MachineId, Self::Error> {
+        let id = MachineId(self.next_id);
+        self.next_id += 1;
+
+        self.states.insert(id, initial.clone());
+        self.events.insert(id, Vec::new());
+
+        Ok(id)
+    }
+
+    fn insert_event(&mut self, id: MachineId, event: &Event) -> Result<(), Self::Error> {
+        self.events
+            .get_mut(&id)
+            .ok_or(InMemoryStorageError::UnknownMachine(id))?
+            .push(event.clone());
+
+        Ok(())
+    }
+
+    fn insert_state(&mut self, id: MachineId, state: &StateType) -> Result<(), Self::Error> {
+        *self
+            .states
+            .get_mut(&id)
+            .ok_or(InMemoryStorageError::UnknownMachine(id))? = state.clone();
+
+        Ok(())
+    }
+
+    fn get_machine(&self, id: MachineId) -> Result<StateType, Self::Error> {
+        self.states
+            .get(&id)
+            .cloned()
+            .ok_or(InMemoryStorageError::UnknownMachine(id))
+    }
+
+    fn get_events(&self, id: MachineId) -> Result<Vec<Event>, Self::Error> {
+        self.events
+            .get(&id)
+            .cloned()
+            .ok_or(InMemoryStorageError::UnknownMachine(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+    enum TurnstileState {
+        Locked,
+        Unlocked,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+    enum TurnstileEvent {
+        PaymentReceived,
+        PersonEntering,
+    }
+
+    impl State<TurnstileEvent> for TurnstileState {
+        type Action = ();
+
+        fn apply(
+            &self,
+            event: TurnstileEvent,
+        ) -> Result<
+            Result<(Self, Vec<Self::Action>), MachineError<Self, TurnstileEvent>>,
+            FatalError<Self, TurnstileEvent>,
+        > {
+            match (self, event) {
+                (Self::Locked, TurnstileEvent::PaymentReceived) => Ok(Ok((Self::Unlocked, vec![]))),
+                (Self::Unlocked, TurnstileEvent::PersonEntering) => Ok(Ok((Self::Locked, vec![]))),
+                (state, event) => Ok(Err(MachineError::InvalidEvent(*state, event))),
+            }
+        }
+
+        fn act(_action: Self::Action) -> Vec<TurnstileEvent> {
+            vec![]
+        }
+    }
+
+    #[test]
+    fn in_memory_storage_round_trips_state_and_events() {
+        let mut storage = InMemoryStorage::new();
+        let id = storage.insert_machine(&TurnstileState::Locked).unwrap();
+
+        storage
+            .insert_event(id, &TurnstileEvent::PaymentReceived)
+            .unwrap();
+        storage
+            .insert_state(id, &TurnstileState::Unlocked)
+            .unwrap();
+
+        assert_eq!(storage.get_machine(id), Ok(TurnstileState::Unlocked));
+        assert_eq!(
+            storage.get_events(id),
+            Ok(vec![TurnstileEvent::PaymentReceived])
+        );
+    }
+
+    #[test]
+    fn in_memory_storage_reports_unknown_machine() {
+        let storage: InMemoryStorage<TurnstileState, TurnstileEvent> = InMemoryStorage::new();
+        let unknown = MachineId(42);
+
+        assert_eq!(
+            storage.get_machine(unknown),
+            Err(InMemoryStorageError::UnknownMachine(unknown))
+        );
+    }
+
+    #[test]
+    fn dispatch_persists_event_and_state_on_success() {
+        let storage = InMemoryStorage::new();
+        let mut machine = PersistentMachine::new(storage, TurnstileState::Locked).unwrap();
+
+        let outcome = machine.dispatch(TurnstileEvent::PaymentReceived).unwrap();
+        assert_eq!(outcome, Ok(()));
+        assert_eq!(machine.state(), &TurnstileState::Unlocked);
+
+        let id = machine.id();
+        let (storage, _) = split(machine);
+        assert_eq!(
+            storage.get_events(id),
+            Ok(vec![TurnstileEvent::PaymentReceived])
+        );
+        assert_eq!(storage.get_machine(id), Ok(TurnstileState::Unlocked));
+    }
+
+    #[test]
+    fn dispatch_does_not_persist_a_rejected_event() {
+        let storage = InMemoryStorage::new();
+        let mut machine = PersistentMachine::new(storage, TurnstileState::Locked).unwrap();
+
+        // rejected: paying twice in a row without entering in between
+        machine
+            .dispatch(TurnstileEvent::PaymentReceived)
+            .unwrap()
+            .unwrap();
+        let outcome = machine.dispatch(TurnstileEvent::PaymentReceived).unwrap();
+        assert_eq!(
+            outcome,
+            Err(MachineError::InvalidEvent(
+                TurnstileState::Unlocked,
+                TurnstileEvent::PaymentReceived
+            ))
+        );
+
+        // the rejected event above should never have reached the log, so the
+        // only thing replay can reconstruct is the single successful payment
+        let id = machine.id();
+        let (storage, _) = split(machine);
+        assert_eq!(
+            storage.get_events(id),
+            Ok(vec![TurnstileEvent::PaymentReceived])
+        );
+        let rehydrated =
+            PersistentMachine::rehydrate_by_replay(storage, id, TurnstileState::Locked).unwrap();
+        assert_eq!(rehydrated.state(), &TurnstileState::Unlocked);
+    }
+
+    #[test]
+    fn rehydrate_by_replay_reconstructs_state_from_the_event_log() {
+        let storage = InMemoryStorage::new();
+        let mut machine = PersistentMachine::new(storage, TurnstileState::Locked).unwrap();
+
+        machine
+            .dispatch(TurnstileEvent::PaymentReceived)
+            .unwrap()
+            .unwrap();
+        machine
+            .dispatch(TurnstileEvent::PersonEntering)
+            .unwrap()
+            .unwrap();
+        machine
+            .dispatch(TurnstileEvent::PaymentReceived)
+            .unwrap()
+            .unwrap();
+
+        let id = machine.id();
+        let (storage, _) = split(machine);
+
+        let rehydrated =
+            PersistentMachine::rehydrate_by_replay(storage, id, TurnstileState::Locked).unwrap();
+        assert_eq!(rehydrated.state(), &TurnstileState::Unlocked);
+    }
+
+    #[test]
+    fn rehydrate_by_replay_tolerates_a_recoverable_error_in_the_log() {
+        // a hand-written log (as from another backend) that contains a
+        // rejected event should still replay, exactly as it behaved live.
+        let mut storage = InMemoryStorage::new();
+        let id = storage.insert_machine(&TurnstileState::Locked).unwrap();
+        storage
+            .insert_event(id, &TurnstileEvent::PersonEntering)
+            .unwrap();
+        storage
+            .insert_event(id, &TurnstileEvent::PaymentReceived)
+            .unwrap();
+
+        let rehydrated =
+            PersistentMachine::rehydrate_by_replay(storage, id, TurnstileState::Locked).unwrap();
+        assert_eq!(rehydrated.state(), &TurnstileState::Unlocked);
+    }
+
+    #[test]
+    fn rehydrate_loads_the_last_saved_state_without_replaying() {
+        let storage = InMemoryStorage::new();
+        let mut machine = PersistentMachine::new(storage, TurnstileState::Locked).unwrap();
+        machine
+            .dispatch(TurnstileEvent::PaymentReceived)
+            .unwrap()
+            .unwrap();
+
+        let id = machine.id();
+        let (mut storage, _) = split(machine);
+
+        // extend the log without updating the saved state: replaying
+        // [PaymentReceived, PersonEntering] would give Locked, so getting
+        // Unlocked back shows the saved state was loaded as-is
+        storage
+            .insert_event(id, &TurnstileEvent::PersonEntering)
+            .unwrap();
+
+        let rehydrated = PersistentMachine::rehydrate(storage, id).unwrap();
+        assert_eq!(rehydrated.state(), &TurnstileState::Unlocked);
+    }
+
+    /// Test-only helper to get the storage back out of a `PersistentMachine`
+    /// so a later test step can rehydrate from the same backend.
+    fn split<S, StateType, Event>(
+        machine: PersistentMachine<S, StateType, Event>,
+    ) -> (S, Machine<StateType, Event>)
+    where
+        S: Storage<StateType, Event>,
+        StateType: State<Event> + Serialize + DeserializeOwned,
+        Event: Serialize + DeserializeOwned,
+    {
+        let PersistentMachine {
+            storage, machine, ..
+        } = machine;
+        (storage, machine)
+    }
+}